@@ -1,8 +1,9 @@
 use std::{
-    collections::HashMap,
-    fs::{create_dir_all, read, File},
+    collections::{BTreeMap, BTreeSet, HashMap},
+    env::current_dir,
+    fs::{create_dir_all, read, File, OpenOptions},
     io::{stdin, Read, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
 use anyhow::Context;
@@ -48,6 +49,12 @@ struct Args {
     /// add attribute to tonic server.
     #[arg(long)]
     server_attribute: Vec<String>,
+    /// add attribute to the tonic client module (the `pub mod xxx_client {}` wrapper). In the form of `path=attribute`.
+    #[arg(long)]
+    client_mod_attribute: Vec<String>,
+    /// add attribute to the tonic server module (the `pub mod xxx_server {}` wrapper). In the form of `path=attribute`.
+    #[arg(long)]
+    server_mod_attribute: Vec<String>,
 
     /// module a specific input file to a specific output file
     /// the map should be in the format of `path/to/input.proto=path/to/output.rs`.
@@ -59,6 +66,18 @@ struct Args {
     #[arg(long)]
     module_in_file: Vec<String>,
 
+    /// write a directory tree of one file per package instead of a single output file.
+    /// each package's dotted name (e.g. `a.b.c`) is split into path segments (the same
+    /// sanitized identifiers prost-build already derives for the package) and written to
+    /// `a/b/c.rs` under this directory (or `a/b/c/mod.rs` if another package nests underneath
+    /// it), with `mod.rs` files synthesized along the way declaring `pub mod <child>;` for
+    /// every child package. Packages with no name are written to `0_no_package_<n>.rs` at the
+    /// root and `include!`d from the root `mod.rs`, since they have no name to declare a
+    /// `pub mod` for.
+    /// Takes precedence over `--output` / `--output-map` / `--module-in-file`.
+    #[arg(long)]
+    tree_out: Option<PathBuf>,
+
     /// create directories
     #[arg(long)]
     create_directory: bool,
@@ -68,6 +87,33 @@ struct Args {
     /// the value should be `crate::PROTO_DEF`.
     #[arg(long)]
     proto_reflect_byte: Option<String>,
+
+    /// generate server code.
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    build_server: bool,
+    /// generate client code.
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    build_client: bool,
+    /// generate transport code.
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    build_transport: bool,
+
+    /// compile `google.protobuf.*` well-known types locally instead of mapping them to the
+    /// `prost-types` crate, e.g. to add serde or reflect derives on them.
+    #[arg(long)]
+    compile_well_known_types: bool,
+    /// suppress doc-comment generation for the given proto path. may be repeated.
+    #[arg(long)]
+    disable_comments: Vec<String>,
+
+    /// write the encoded `FileDescriptorSet` to this path, for use with
+    /// `tonic_reflection::server::Builder::register_encoded_file_descriptor_set`.
+    #[arg(long)]
+    descriptor_set_out: Option<PathBuf>,
+    /// name of a `pub const NAME: &[u8]` appended to the generated output that
+    /// `include_bytes!`s the file at `--descriptor-set-out`. Requires `--descriptor-set-out`.
+    #[arg(long)]
+    descriptor_const: Option<String>,
 }
 
 fn split_arg(s: &str) -> (&str, &str) {
@@ -89,6 +135,191 @@ fn write_with_module(f: &mut impl Write, content: &str, modules: &[&str]) {
     }
 }
 
+/// resolves a module that may have several input files to a single value from `map`, keyed
+/// by those input files. Returns `None` if none of the input files are mapped, and panics if
+/// more than one of them is mapped to a different value, since that's a genuine ambiguity.
+fn resolve_mapped<T: Clone + PartialEq>(
+    module: &Module,
+    input_files: &[&str],
+    map: &HashMap<&str, T>,
+) -> Option<T> {
+    let mut resolved: Option<&T> = None;
+    for input_file in input_files {
+        if let Some(v) = map.get(input_file) {
+            match resolved {
+                None => resolved = Some(v),
+                Some(existing) if existing == v => {}
+                Some(_) => panic!(
+                    "module {} has conflicting output targets across its input files: {:?}",
+                    module, input_files
+                ),
+            }
+        }
+    }
+    resolved.cloned()
+}
+
+/// `include_bytes!` resolves a relative path against the directory of the file it's written
+/// into, not against the current working directory, so a path collected from CLI args (which
+/// is relative to the working directory) has to be re-based onto `from_dir` before embedding.
+fn relative_include_path(path: &Path, from_dir: &Path) -> PathBuf {
+    let absolutize = |p: &Path| -> PathBuf {
+        if p.is_absolute() {
+            p.to_path_buf()
+        } else {
+            current_dir().unwrap().join(p)
+        }
+    };
+    let path = absolutize(path);
+    let from_dir = absolutize(from_dir);
+
+    let path_components: Vec<_> = path.components().collect();
+    let from_components: Vec<_> = from_dir.components().collect();
+    let common = path_components
+        .iter()
+        .zip(from_components.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut result = PathBuf::new();
+    for _ in common..from_components.len() {
+        result.push("..");
+    }
+    for component in &path_components[common..] {
+        result.push(component.as_os_str());
+    }
+    result
+}
+
+/// writes `modules` as a directory tree rooted at `tree_out`, one file per package plus
+/// synthesized `mod.rs` files declaring the package nesting. See `Args::tree_out`.
+/// `descriptor_const`/`descriptor_set_out`, if given, append a
+/// `pub const NAME: &[u8] = include_bytes!(...)` declaration to the root `mod.rs`, mirroring
+/// what the single-file output does (see `Args::descriptor_const`).
+fn write_tree(
+    tree_out: &Path,
+    modules: &HashMap<Module, String>,
+    create_directory: bool,
+    descriptor_const: Option<&str>,
+    descriptor_set_out: Option<&Path>,
+) {
+    let mut contents: BTreeMap<Vec<String>, &str> = BTreeMap::new();
+    let mut children: BTreeMap<Vec<String>, BTreeSet<String>> = BTreeMap::new();
+    // files with no package, named so the leading digit makes them impossible to confuse with
+    // a sanitized package segment (those are always valid Rust identifiers, which can't start
+    // with a digit), and indexed so several package-less inputs don't clobber each other.
+    let mut no_package_files: Vec<String> = Vec::new();
+
+    if create_directory {
+        create_dir_all(tree_out)
+            .with_context(|| format!("failed to create directory {:?}", tree_out))
+            .unwrap();
+    }
+
+    for (module, content) in modules {
+        let segments: Vec<String> = module.parts().map(str::to_owned).collect();
+        if segments.is_empty() {
+            let file_name = format!("0_no_package_{}.rs", no_package_files.len());
+            let path = tree_out.join(&file_name);
+            let mut f = File::create(&path)
+                .with_context(|| format!("failed to create file {:?}", path))
+                .unwrap();
+            writeln!(f, "{}", content).unwrap();
+            no_package_files.push(file_name);
+            continue;
+        }
+
+        for i in 0..segments.len() {
+            children
+                .entry(segments[..i].to_vec())
+                .or_default()
+                .insert(segments[i].clone());
+        }
+        contents.insert(segments, content.as_str());
+    }
+
+    write_tree_dir(tree_out, &[], &contents, &children, create_directory);
+
+    if !no_package_files.is_empty() || descriptor_const.is_some() {
+        let mod_path = tree_out.join("mod.rs");
+        let mut mod_file = OpenOptions::new()
+            .append(true)
+            .open(&mod_path)
+            .with_context(|| format!("failed to open file {:?}", mod_path))
+            .unwrap();
+
+        // package-less content has no name to hang a `pub mod` off, so pull it in directly
+        // instead of leaving it as an unreachable file.
+        for file_name in &no_package_files {
+            writeln!(mod_file, r#"include!("{}");"#, file_name).unwrap();
+        }
+
+        if let Some(descriptor_const) = descriptor_const {
+            let descriptor_set_out = descriptor_set_out
+                .expect("--descriptor-const requires --descriptor-set-out");
+            let include_path = relative_include_path(descriptor_set_out, tree_out);
+            writeln!(
+                mod_file,
+                r#"pub const {}: &[u8] = include_bytes!("{}");"#,
+                descriptor_const,
+                include_path.display(),
+            )
+            .unwrap();
+        }
+    }
+}
+
+fn write_tree_dir(
+    tree_out: &Path,
+    prefix: &[String],
+    contents: &BTreeMap<Vec<String>, &str>,
+    children: &BTreeMap<Vec<String>, BTreeSet<String>>,
+    create_directory: bool,
+) {
+    let dir = prefix
+        .iter()
+        .fold(tree_out.to_path_buf(), |p, segment| p.join(segment));
+
+    if create_directory {
+        create_dir_all(&dir)
+            .with_context(|| format!("failed to create directory {:?}", dir))
+            .unwrap();
+    }
+
+    let mod_path = dir.join("mod.rs");
+    let mut mod_file = File::create(&mod_path)
+        .with_context(|| format!("failed to create file {:?}", mod_path))
+        .unwrap();
+    if let Some(content) = contents.get(prefix) {
+        writeln!(mod_file, "{}", content).unwrap();
+    }
+
+    let Some(child_segments) = children.get(prefix) else {
+        return;
+    };
+    for child in child_segments {
+        writeln!(mod_file, "pub mod {};", child).unwrap();
+    }
+    drop(mod_file);
+
+    for child in child_segments {
+        let mut child_path = prefix.to_vec();
+        child_path.push(child.clone());
+
+        if children.contains_key(&child_path) {
+            write_tree_dir(tree_out, &child_path, contents, children, create_directory);
+        } else {
+            let leaf_path = dir.join(format!("{}.rs", child));
+            let mut leaf_file = File::create(&leaf_path)
+                .with_context(|| format!("failed to create file {:?}", leaf_path))
+                .unwrap();
+            if let Some(content) = contents.get(&child_path) {
+                writeln!(leaf_file, "{}", content).unwrap();
+            }
+        }
+    }
+}
+
 fn main() {
     let args = Args::parse();
 
@@ -129,9 +360,30 @@ fn main() {
         let (a, b) = split_arg(x);
         tonic_build = tonic_build.client_attribute(a, b);
     }
+    for x in args.client_mod_attribute.iter() {
+        let (a, b) = split_arg(x);
+        tonic_build = tonic_build.client_mod_attribute(a, b);
+    }
+    for x in args.server_mod_attribute.iter() {
+        let (a, b) = split_arg(x);
+        tonic_build = tonic_build.server_mod_attribute(a, b);
+    }
+
+    if args.compile_well_known_types {
+        prost_config.compile_well_known_types();
+        tonic_build = tonic_build.compile_well_known_types(true);
+    }
+    for x in args.disable_comments.iter() {
+        prost_config.disable_comments([x.as_str()]);
+        tonic_build = tonic_build.disable_comments(x.as_str());
+    }
 
     prost_config.skip_protoc_run();
-    tonic_build = tonic_build.skip_protoc_run();
+    tonic_build = tonic_build
+        .skip_protoc_run()
+        .build_server(args.build_server)
+        .build_client(args.build_client)
+        .build_transport(args.build_transport);
 
     prost_config.service_generator(tonic_build.service_generator());
 
@@ -150,6 +402,22 @@ fn main() {
 
     let file_descriptor_set = FileDescriptorSet::decode(&*buf).unwrap();
 
+    if let Some(descriptor_set_out) = args.descriptor_set_out.as_ref() {
+        if args.create_directory {
+            if let Some(parent) = descriptor_set_out.parent() {
+                create_dir_all(parent)
+                    .with_context(|| format!("failed to create directory {:?}", parent))
+                    .unwrap();
+            }
+        }
+        File::create(descriptor_set_out)
+            .with_context(|| format!("failed to create file {:?}", descriptor_set_out))
+            .unwrap()
+            .write_all(&file_descriptor_set.encode_to_vec())
+            .with_context(|| format!("failed to write file {:?}", descriptor_set_out))
+            .unwrap();
+    }
+
     if let Some(proto_reflect_bytes) = args.proto_reflect_byte {
         let descriptor = DescriptorPool::decode(&*buf).unwrap();
         let pool_attribute = format!(
@@ -168,18 +436,16 @@ fn main() {
         }
     }
 
-    let mut module_to_input: HashMap<Module, &str> = HashMap::new();
+    // several .proto files can declare the same package, so a module maps to all of the
+    // input files that contributed to it, not just one.
+    let mut module_to_input: HashMap<Module, Vec<&str>> = HashMap::new();
 
     let request = file_descriptor_set
         .file
         .iter()
         .map(|d| {
             let m = Module::from_protobuf_package_name(d.package());
-            if module_to_input.contains_key(&m) {
-                panic!("module duplicate: {}", m);
-            }
-
-            module_to_input.insert(m.clone(), d.name());
+            module_to_input.entry(m.clone()).or_default().push(d.name());
             (m, d.to_owned())
         })
         .collect();
@@ -188,6 +454,17 @@ fn main() {
 
     let mut output_file: Option<File> = None;
 
+    if let Some(tree_out) = args.tree_out.as_ref() {
+        write_tree(
+            tree_out,
+            &modules,
+            args.create_directory,
+            args.descriptor_const.as_deref(),
+            args.descriptor_set_out.as_deref(),
+        );
+        return;
+    }
+
     let mut output_map: HashMap<&str, PathBuf> = HashMap::new();
     for x in args.output_map.iter() {
         let (input_file, output_file_name) = split_arg(x);
@@ -201,13 +478,11 @@ fn main() {
     }
 
     for (module, content) in &modules {
-        let input_file = module_to_input.get(module).unwrap();
-        let modules_in_file = match module_in_file_map.get(input_file) {
-            Some(x) => x.clone(),
-            None => vec![],
-        };
+        let input_files = module_to_input.get(module).unwrap();
+        let modules_in_file =
+            resolve_mapped(module, input_files, &module_in_file_map).unwrap_or_default();
 
-        match output_map.get(input_file) {
+        match resolve_mapped(module, input_files, &output_map) {
             Some(p) => {
                 if args.create_directory {
                     if let Some(parent) = p.parent() {
@@ -216,7 +491,7 @@ fn main() {
                             .unwrap();
                     }
                 }
-                let mut output = File::create(p)
+                let mut output = File::create(&p)
                     .with_context(|| format!("failed to create file {:?}", p))
                     .unwrap();
                 write_with_module(&mut output, content, &modules_in_file);
@@ -244,4 +519,27 @@ fn main() {
             }
         }
     }
+
+    if let Some(descriptor_const) = args.descriptor_const.as_ref() {
+        let descriptor_set_out = args
+            .descriptor_set_out
+            .as_ref()
+            .expect("--descriptor-const requires --descriptor-set-out");
+        let output_path = args
+            .output
+            .as_ref()
+            .expect("--descriptor-const requires --output");
+        if output_file.is_none() {
+            output_file = Some(File::create(output_path).unwrap());
+        }
+        let output_dir = output_path.parent().unwrap_or_else(|| Path::new("."));
+        let include_path = relative_include_path(descriptor_set_out, output_dir);
+        writeln!(
+            output_file.as_ref().unwrap(),
+            r#"pub const {}: &[u8] = include_bytes!("{}");"#,
+            descriptor_const,
+            include_path.display(),
+        )
+        .unwrap();
+    }
 }